@@ -0,0 +1,64 @@
+mod merkle_tree;
+pub mod plotting;
+
+use parity_scale_codec::{Decode, Encode};
+use std::io;
+use std::num::NonZeroU64;
+use subspace_core_primitives::crypto::{blake2b_256_hash, Blake2b256Hash};
+use subspace_core_primitives::{Piece, PieceIndex};
+use thiserror::Error;
+
+pub use merkle_tree::{verify, PieceInclusionProof, PieceMerkleTree};
+
+/// Metadata of a single disk plot sector, stored next to the plotted pieces on disk.
+#[derive(Debug, Clone, Encode, Decode)]
+pub struct SectorMetadata {
+    /// Total number of pieces in the blockchain history when the sector was plotted
+    pub total_pieces: NonZeroU64,
+    /// Segment index at which sector expires
+    pub expires_at: u64,
+    /// Root of the incremental Merkle tree committing to every encoded piece in the sector, in
+    /// `piece_offset` order
+    pub pieces_root: Blake2b256Hash,
+}
+
+/// Write journal tracking plotting progress for a single sector, so that an interrupted or
+/// crashed plot can resume at the first unwritten `piece_offset` instead of starting over.
+#[derive(Debug, Clone, Encode, Decode, Default)]
+pub struct SectorPlottingJournal {
+    /// Number of pieces, in `piece_offset` order, confirmed durably written to the sector so far
+    pub pieces_written: u64,
+    /// Merkle leaf hash of the last piece counted in `pieces_written`, used on resume to detect a
+    /// torn write of that piece (e.g. a crash mid-write) and redo it
+    pub last_piece_hash: Blake2b256Hash,
+}
+
+/// Verify that `piece` was committed to in `sector_metadata.pieces_root` at its `piece_offset`,
+/// using an inclusion proof produced by [`PieceMerkleTree::proof`] while the sector was plotted.
+pub fn verify_piece_inclusion(
+    piece: &Piece,
+    proof: &PieceInclusionProof,
+    sector_metadata: &SectorMetadata,
+) -> bool {
+    verify(blake2b_256_hash(piece), proof, sector_metadata.pieces_root)
+}
+
+/// Errors happening during plotting
+#[derive(Debug, Error)]
+pub enum PlottingError {
+    /// Failed to retrieve piece
+    #[error("Failed to retrieve piece {piece_index}: {error}")]
+    FailedToRetrievePiece {
+        piece_index: PieceIndex,
+        error: Box<dyn std::error::Error + Send + Sync + 'static>,
+    },
+    /// Piece not found
+    #[error("Piece {piece_index} not found")]
+    PieceNotFound { piece_index: PieceIndex },
+    /// Piece failed validation against its KZG witness
+    #[error("Piece {piece_index} failed validation against its KZG witness")]
+    InvalidPiece { piece_index: PieceIndex },
+    /// I/O error
+    #[error("I/O error: {0}")]
+    Io(#[from] io::Error),
+}
@@ -1,18 +1,34 @@
-use crate::single_disk_plot::{PlottingError, SectorMetadata};
+use crate::single_disk_plot::{
+    PieceMerkleTree, PlottingError, SectorMetadata, SectorPlottingJournal,
+};
 use bitvec::order::Lsb0;
 use bitvec::prelude::*;
-use parity_scale_codec::Encode;
+use futures::stream::FuturesUnordered;
+use futures::StreamExt;
+use parity_scale_codec::{Decode, Encode};
 use rayon::prelude::*;
+use std::collections::BTreeMap;
 use std::future::Future;
 use std::io;
+use std::io::{Read, Seek, SeekFrom};
 use std::sync::atomic::{AtomicBool, Ordering};
-use subspace_core_primitives::crypto::kzg::Witness;
+use subspace_core_primitives::crypto::{blake2b_256_hash, kzg::Witness};
 use subspace_core_primitives::{
     plot_sector_size, Piece, PieceIndex, PublicKey, SectorId, PIECE_SIZE,
 };
+use subspace_networking::utils::piece_receiver::PieceValidator;
 use subspace_rpc_primitives::FarmerProtocolInfo;
 use subspace_solving::derive_chunk_otp;
-use tracing::debug;
+use tracing::{debug, warn};
+
+/// Default number of `get_piece` requests `plot_sector` keeps in flight at once, used when the
+/// caller doesn't have a more specific value to pass in.
+pub const DEFAULT_PIECE_PREFETCH_DEPTH: usize = 20;
+
+/// How many times a piece is fetched and checked against its witness, in total, before giving up
+/// on the sector with [`PlottingError::InvalidPiece`] (i.e. the initial fetch plus
+/// `MAX_PIECE_VALIDATION_ATTEMPTS - 1` re-fetches).
+const MAX_PIECE_VALIDATION_ATTEMPTS: u32 = 3;
 
 /// Plotting status
 pub enum PlottingStatus {
@@ -22,24 +38,53 @@ pub enum PlottingStatus {
     Interrupted,
 }
 
-/// Plot a single sector, where `sector` and `sector_metadata` must be positioned correctly (seek to
-/// desired offset before calling this function if necessary)
+/// Plot a single sector, where `sector`, `sector_journal` and `sector_metadata` must be positioned
+/// correctly (seek to desired offset before calling this function if necessary)
+///
+/// `piece_prefetch_depth` bounds how many `get_piece` requests are kept in flight at once so DSN
+/// round-trips overlap with encoding instead of serializing the whole sector; use
+/// [`DEFAULT_PIECE_PREFETCH_DEPTH`] absent a more specific value.
+///
+/// When `piece_validator` is set, every fetched piece is passed to its
+/// [`PieceValidator::validate_piece_without_peer`] before being encoded; whether that checks the
+/// piece against its KZG witness depends on the validator, and the trait's default implementation
+/// accepts the piece unchecked. A piece whose witness fails to decode (with or without a
+/// validator) or that the validator rejects is re-fetched rather than used, up to
+/// [`MAX_PIECE_VALIDATION_ATTEMPTS`] fetches total.
+///
+/// `sector_journal`, when set, records, after every piece is durably written, how many pieces
+/// (and the hash of the last one) have made it to `sector` so far. If plotting is interrupted by a
+/// crash (rather than the cooperative `shutting_down` shutdown, which is always clean), calling
+/// this function again with the same `sector` and `sector_journal` resumes from the first
+/// unwritten piece instead of redoing the whole sector; a last piece whose hash no longer matches
+/// the journal (torn by a partial write) is redone as well. Pass `None` to always plot the sector
+/// from scratch, without resume support.
+///
+/// `sector` is flushed before the journal entry for a piece is advanced, so the journal can never
+/// claim a piece durable before it is, but `io::Write::flush` only guarantees the bytes reach the
+/// OS, not the disk. Resume correctness after a real power loss (as opposed to a process crash)
+/// additionally requires `sector` and `sector_journal` to fsync on flush, e.g. by wrapping a
+/// `File` so `flush()` calls `sync_data()`; plain `File`/`BufWriter` do not do this on their own.
 ///
 /// NOTE: Even though this function is async, it has blocking code inside and must be running in a
 /// separate thread in order to prevent blocking an executor.
-pub async fn plot_sector<GP, GPF, S, SM>(
+pub async fn plot_sector<GP, GPF, S, SJ, SM>(
     public_key: &PublicKey,
     sector_index: u64,
     get_piece: GP,
     shutting_down: &AtomicBool,
     farmer_protocol_info: &FarmerProtocolInfo,
+    piece_prefetch_depth: usize,
+    piece_validator: Option<&dyn PieceValidator>,
     mut sector: S,
+    mut sector_journal: Option<SJ>,
     mut sector_metadata: SM,
 ) -> Result<PlottingStatus, PlottingError>
 where
     GP: Fn(PieceIndex) -> GPF,
     GPF: Future<Output = Result<Option<Piece>, Box<dyn std::error::Error + Send + Sync + 'static>>>,
-    S: io::Write,
+    S: io::Write + Read + Seek,
+    SJ: io::Write + Read + Seek,
     SM: io::Write,
 {
     let sector_id = SectorId::new(public_key, sector_index);
@@ -53,7 +98,59 @@ where
         * 2;
     let expires_at = current_segment_index + farmer_protocol_info.sector_expiration;
 
-    for piece_offset in (0..).take(plot_sector_size as usize / PIECE_SIZE) {
+    let num_pieces = plot_sector_size as usize / PIECE_SIZE;
+    let sector_start = sector.stream_position()?;
+
+    let (mut journal, journal_start) = match &mut sector_journal {
+        Some(sector_journal) => {
+            let journal_start = sector_journal.stream_position()?;
+            let mut journal_bytes = Vec::new();
+            sector_journal.read_to_end(&mut journal_bytes)?;
+            let journal =
+                SectorPlottingJournal::decode(&mut journal_bytes.as_slice()).unwrap_or_default();
+            (journal, journal_start)
+        }
+        None => (SectorPlottingJournal::default(), 0),
+    };
+    let mut starting_offset = journal.pieces_written.min(num_pieces as u64);
+
+    // Committed to incrementally as encoded pieces are written, so a proof that piece N is
+    // exactly what was committed to in this sector can later be produced without rehashing it.
+    let mut pieces_merkle_tree = PieceMerkleTree::new();
+
+    if starting_offset > 0 {
+        // Replay the pieces the journal says are already durable, checking that the last one
+        // wasn't torn by a crash mid-write; if it was, it is redone below along with the rest.
+        let mut piece_buffer = vec![0u8; PIECE_SIZE];
+        for piece_offset in 0..starting_offset {
+            sector.seek(SeekFrom::Start(sector_start + piece_offset * PIECE_SIZE as u64))?;
+            sector.read_exact(&mut piece_buffer)?;
+            let leaf = blake2b_256_hash(&piece_buffer);
+
+            if piece_offset == starting_offset - 1 && leaf != journal.last_piece_hash {
+                debug!(%sector_index, %piece_offset, "Last journaled piece is torn, redoing it");
+                starting_offset -= 1;
+                break;
+            }
+
+            pieces_merkle_tree.append(leaf);
+        }
+
+        sector.seek(SeekFrom::Start(sector_start + starting_offset * PIECE_SIZE as u64))?;
+    }
+
+    // Keep up to `piece_prefetch_depth` `get_piece` requests in flight so network latency to the
+    // DSN is overlapped with the CPU-bound encode step below, instead of serializing the whole
+    // sector on round-trips. Pieces are fed into encoding strictly in offset order, so completed
+    // requests that arrive out of order are buffered until their turn comes up.
+    let mut prefetch = BoundedPrefetch::new(
+        starting_offset,
+        num_pieces as u64,
+        piece_prefetch_depth,
+        |piece_offset| fetch_piece(&sector_id, piece_offset, farmer_protocol_info, &get_piece),
+    );
+
+    for piece_offset in starting_offset..num_pieces as u64 {
         if shutting_down.load(Ordering::Acquire) {
             debug!(
                 %sector_index,
@@ -61,33 +158,54 @@ where
             );
             return Ok(PlottingStatus::Interrupted);
         }
+
+        let mut piece = prefetch.wait_for(piece_offset, shutting_down).await?;
+
         let piece_index = sector_id.derive_piece_index(
             piece_offset as PieceIndex,
             farmer_protocol_info.total_pieces,
         );
 
-        let mut piece = get_piece(piece_index)
-            .await
-            .map_err(|error| PlottingError::FailedToRetrievePiece { piece_index, error })?
-            .ok_or(PlottingError::PieceNotFound { piece_index })?;
+        let mut attempts = 0u32;
+        let piece_witness = 'validate: loop {
+            attempts += 1;
 
-        let piece_witness = match Witness::try_from_bytes(
-            &piece[farmer_protocol_info.record_size.get() as usize..]
+            let decoded_witness = piece[farmer_protocol_info.record_size.get() as usize..]
                 .try_into()
-                .expect(
-                    "Witness must have correct size unless implementation \
-                        is broken in a big way; qed",
-                ),
-        ) {
-            Ok(piece_witness) => piece_witness,
-            Err(error) => {
-                // TODO: This will have to change once we pull pieces from
-                //  DSN
-                panic!(
-                    "Failed to decode witness for piece {piece_index}, \
-                    must be a bug on the node: {error:?}"
-                );
+                .ok()
+                .and_then(|bytes| Witness::try_from_bytes(bytes).ok());
+
+            if let Some(witness) = decoded_witness {
+                let validated_piece = match piece_validator {
+                    Some(validator) => {
+                        validator
+                            .validate_piece_without_peer(piece_index, piece)
+                            .await
+                    }
+                    None => Some(piece),
+                };
+
+                match validated_piece {
+                    Some(validated_piece) => {
+                        piece = validated_piece;
+                        break 'validate witness;
+                    }
+                    None => {
+                        // `piece` was consumed by `validate_piece_without_peer`, a fresh one is
+                        // fetched below
+                    }
+                }
+            }
+
+            if attempts >= MAX_PIECE_VALIDATION_ATTEMPTS {
+                return Err(PlottingError::InvalidPiece { piece_index });
             }
+
+            warn!(%piece_index, attempts, "Piece failed validation, re-fetching");
+            piece = get_piece(piece_index)
+                .await
+                .map_err(|error| PlottingError::FailedToRetrievePiece { piece_index, error })?
+                .ok_or(PlottingError::PieceNotFound { piece_index })?;
         };
         // TODO: We are skipping witness part of the piece or else it is not
         //  decodable
@@ -112,16 +230,206 @@ where
                     });
             });
 
+        let leaf = blake2b_256_hash(&piece);
+        pieces_merkle_tree.append(leaf);
         sector.write_all(&piece)?;
-    }
+        // The journal must not claim a piece as durable before the piece itself is, or resume
+        // (which only re-validates the *last* journaled piece, see above) could skip over a piece
+        // that never actually made it to disk.
+        sector.flush()?;
 
-    sector_metadata.write_all(
-        &SectorMetadata {
-            total_pieces: farmer_protocol_info.total_pieces,
-            expires_at,
+        if let Some(sector_journal) = &mut sector_journal {
+            journal.pieces_written = piece_offset + 1;
+            journal.last_piece_hash = leaf;
+            sector_journal.seek(SeekFrom::Start(journal_start))?;
+            sector_journal.write_all(&journal.encode())?;
+            sector_journal.flush()?;
         }
-        .encode(),
-    )?;
+    }
+
+    let sector_metadata_value = SectorMetadata {
+        total_pieces: farmer_protocol_info.total_pieces,
+        expires_at,
+        pieces_root: pieces_merkle_tree.root(),
+    };
+
+    #[cfg(debug_assertions)]
+    if let Some(proof) = pieces_merkle_tree.proof(0) {
+        // Defense-in-depth: a freshly produced inclusion proof for the first piece of the sector
+        // must verify against the root being committed to, or `PieceMerkleTree` and `verify()`
+        // have drifted out of sync with each other.
+        let mut first_piece = vec![0u8; PIECE_SIZE];
+        sector.seek(SeekFrom::Start(sector_start))?;
+        sector.read_exact(&mut first_piece)?;
+        let leaf = blake2b_256_hash(&first_piece);
+        debug_assert!(
+            super::verify(leaf, &proof, sector_metadata_value.pieces_root),
+            "inclusion proof for the first piece of sector {sector_index} did not verify \
+            against its own freshly computed root",
+        );
+    }
+
+    sector_metadata.write_all(&sector_metadata_value.encode())?;
 
     Ok(PlottingStatus::PlottedSuccessfully)
 }
+
+/// Request the piece at `piece_offset` from the DSN, tagging the result with its offset so
+/// out-of-order completions from a [`FuturesUnordered`] of these can be matched back up.
+fn fetch_piece<'a, GP, GPF>(
+    sector_id: &'a SectorId,
+    piece_offset: u64,
+    farmer_protocol_info: &'a FarmerProtocolInfo,
+    get_piece: &'a GP,
+) -> impl Future<Output = (u64, Result<Piece, PlottingError>)> + 'a
+where
+    GP: Fn(PieceIndex) -> GPF,
+    GPF: Future<Output = Result<Option<Piece>, Box<dyn std::error::Error + Send + Sync + 'static>>> + 'a,
+{
+    async move {
+        let piece_index = sector_id
+            .derive_piece_index(piece_offset as PieceIndex, farmer_protocol_info.total_pieces);
+
+        let result = get_piece(piece_index)
+            .await
+            .map_err(|error| PlottingError::FailedToRetrievePiece { piece_index, error })
+            .and_then(|maybe_piece| maybe_piece.ok_or(PlottingError::PieceNotFound { piece_index }));
+
+        (piece_offset, result)
+    }
+}
+
+/// Keeps up to `prefetch_depth` calls to a `fetch` function in flight for a range of offsets,
+/// buffering completions that arrive out of order so [`Self::wait_for`] can always deliver them
+/// strictly in ascending offset order.
+struct BoundedPrefetch<F, FF, T> {
+    fetch: F,
+    in_flight: FuturesUnordered<FF>,
+    fetched: BTreeMap<u64, T>,
+    next_offset_to_fetch: u64,
+    end_offset: u64,
+}
+
+impl<F, FF, T, E> BoundedPrefetch<F, FF, T>
+where
+    F: Fn(u64) -> FF,
+    FF: Future<Output = (u64, Result<T, E>)>,
+{
+    /// Start prefetching `starting_offset..end_offset`, keeping up to `prefetch_depth` calls to
+    /// `fetch` in flight at once (at least one, even if `prefetch_depth` is `0`).
+    fn new(starting_offset: u64, end_offset: u64, prefetch_depth: usize, fetch: F) -> Self {
+        let remaining = end_offset.saturating_sub(starting_offset);
+        let initial_in_flight = prefetch_depth.max(1).min(remaining as usize);
+        let in_flight = (starting_offset..starting_offset + initial_in_flight as u64)
+            .map(&fetch)
+            .collect::<FuturesUnordered<_>>();
+
+        Self {
+            next_offset_to_fetch: starting_offset + initial_in_flight as u64,
+            in_flight,
+            fetched: BTreeMap::new(),
+            end_offset,
+            fetch,
+        }
+    }
+
+    /// Wait until `offset`'s result is ready, draining (and buffering) other in-flight results in
+    /// the meantime and topping the prefetch window back up as they complete. Once
+    /// `shutting_down` is observed, no further offsets are submitted, though results already in
+    /// flight are still drained and buffered.
+    async fn wait_for(&mut self, offset: u64, shutting_down: &AtomicBool) -> Result<T, E> {
+        while !self.fetched.contains_key(&offset) {
+            let (ready_offset, result) = self
+                .in_flight
+                .next()
+                .await
+                .expect("there is always at least one piece in flight until all are fetched; qed");
+            self.fetched.insert(ready_offset, result?);
+
+            if !shutting_down.load(Ordering::Acquire) && self.next_offset_to_fetch < self.end_offset
+            {
+                self.in_flight.push((self.fetch)(self.next_offset_to_fetch));
+                self.next_offset_to_fetch += 1;
+            }
+        }
+
+        Ok(self
+            .fetched
+            .remove(&offset)
+            .expect("just inserted into the map above; qed"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::AtomicUsize;
+    use std::sync::Arc;
+    use std::time::Duration;
+    use tokio::time::sleep;
+
+    /// Returns `offset * 2` after a delay that makes offsets complete out of order (evens are
+    /// slower than odds), so a test driving this has to actually buffer and reorder rather than
+    /// pieces happening to arrive in the order they were requested.
+    fn delayed_fetch(offset: u64) -> impl Future<Output = (u64, Result<u64, ()>)> {
+        async move {
+            let delay_ms = if offset % 2 == 0 { 20 } else { 1 };
+            sleep(Duration::from_millis(delay_ms)).await;
+            (offset, Ok(offset * 2))
+        }
+    }
+
+    #[tokio::test]
+    async fn bounded_prefetch_delivers_in_order_despite_out_of_order_completion() {
+        let shutting_down = AtomicBool::new(false);
+        let mut prefetch = BoundedPrefetch::new(0, 6, 3, delayed_fetch);
+
+        for offset in 0..6u64 {
+            let value = prefetch
+                .wait_for(offset, &shutting_down)
+                .await
+                .expect("delayed_fetch never fails");
+            assert_eq!(value, offset * 2);
+        }
+    }
+
+    #[tokio::test]
+    async fn bounded_prefetch_works_with_depth_of_one() {
+        let shutting_down = AtomicBool::new(false);
+        let mut prefetch = BoundedPrefetch::new(0, 4, 1, delayed_fetch);
+
+        for offset in 0..4u64 {
+            let value = prefetch
+                .wait_for(offset, &shutting_down)
+                .await
+                .expect("delayed_fetch never fails");
+            assert_eq!(value, offset * 2);
+        }
+    }
+
+    #[tokio::test]
+    async fn bounded_prefetch_stops_submitting_once_shutting_down() {
+        let shutting_down = Arc::new(AtomicBool::new(false));
+        let fetch_count = Arc::new(AtomicUsize::new(0));
+
+        let fetch_count_for_fetch = Arc::clone(&fetch_count);
+        let fetch = move |offset: u64| {
+            fetch_count_for_fetch.fetch_add(1, Ordering::SeqCst);
+            async move { (offset, Ok::<_, ()>(offset)) }
+        };
+
+        // `prefetch_depth` of 2 means offsets 0 and 1 are submitted immediately.
+        let mut prefetch = BoundedPrefetch::new(0, 100, 2, fetch);
+        assert_eq!(fetch_count.load(Ordering::SeqCst), 2);
+
+        shutting_down.store(true, Ordering::SeqCst);
+        prefetch
+            .wait_for(0, &shutting_down)
+            .await
+            .expect("fetch never fails in this test");
+
+        // Draining the already in-flight offsets must not submit offset 2 once shutting_down is
+        // observed.
+        assert_eq!(fetch_count.load(Ordering::SeqCst), 2);
+    }
+}
@@ -54,12 +54,31 @@ impl From<StorageType> for MultihashCode {
 
 #[async_trait]
 pub trait PieceValidator: Sync + Send {
+    /// Validate `piece` against `piece_index`, returning it back if valid or `None` otherwise.
+    ///
+    /// `source_peer_id` is the peer the piece was requested from, used for attributing failed
+    /// validation.
     async fn validate_piece(
         &self,
         source_peer_id: PeerId,
         piece_index: PieceIndex,
         piece: Piece,
     ) -> Option<Piece>;
+
+    /// Same as [`Self::validate_piece`], but for a piece obtained outside of DSN peer retrieval
+    /// (e.g. while plotting), where there is no specific peer to attribute a failed validation
+    /// to.
+    ///
+    /// The default implementation accepts the piece unchecked; validators that can meaningfully
+    /// validate a piece without a source peer (e.g. by checking it against a KZG witness) should
+    /// override this.
+    async fn validate_piece_without_peer(
+        &self,
+        _piece_index: PieceIndex,
+        piece: Piece,
+    ) -> Option<Piece> {
+        Some(piece)
+    }
 }
 
 /// Piece provider with cancellation and optional piece validator.
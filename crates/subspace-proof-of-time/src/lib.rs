@@ -4,12 +4,14 @@
 mod aes;
 
 use core::num::NonZeroU32;
+use core::slice;
+use rayon::prelude::*;
 use subspace_core_primitives::{PotCheckpoints, PotOutput, PotSeed};
 use std::time;
 use tracing::info;
 
 /// Proof of time error
-#[derive(Debug)]
+#[derive(Debug, PartialEq, Eq)]
 #[cfg_attr(feature = "thiserror", derive(thiserror::Error))]
 pub enum PotError {
     /// Iterations is not multiple of number of checkpoints times two
@@ -77,3 +79,92 @@ pub fn verify(
     info!("verify slot time {:?}",duration);
     res
 }
+
+/// Verify checkpoints the same way as [`verify`], but check every checkpoint segment
+/// concurrently instead of walking through `checkpoints` sequentially.
+///
+/// Each segment `i` is independently verifiable: it starts from checkpoint `i - 1` (or `seed` for
+/// segment 0) and must reproduce checkpoint `i` after `iterations / checkpoints.len()` AES steps,
+/// so this checks all of them in parallel and only returns `true` if every segment reproduces its
+/// boundary checkpoint. Intended for consensus verification, which can spend all available cores;
+/// proving itself must remain sequential.
+///
+/// Returns error if `iterations` is not a multiple of checkpoints times two.
+pub fn verify_parallel(
+    seed: PotSeed,
+    iterations: NonZeroU32,
+    checkpoints: &[PotOutput],
+) -> Result<bool, PotError> {
+    let now = time::Instant::now();
+    let num_checkpoints = checkpoints.len() as u32;
+    if iterations.get() % (num_checkpoints * 2) != 0 {
+        return Err(PotError::NotMultipleOfCheckpoints {
+            iterations,
+            num_checkpoints,
+        });
+    }
+
+    let iterations_per_checkpoint = iterations.get() / num_checkpoints;
+    let key = seed.key();
+
+    let res = Ok(checkpoints
+        .par_iter()
+        .enumerate()
+        .all(|(segment_index, checkpoint)| {
+            let segment_seed = match segment_index.checked_sub(1) {
+                Some(previous_index) => PotSeed::from(checkpoints[previous_index]),
+                None => seed,
+            };
+
+            aes::verify_sequential(
+                segment_seed,
+                key,
+                slice::from_ref(checkpoint),
+                iterations_per_checkpoint,
+            )
+        }));
+    let duration = now.elapsed();
+    info!("verify (parallel) slot time {:?}", duration);
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_iterations() -> NonZeroU32 {
+        NonZeroU32::new(u32::from(PotCheckpoints::NUM_CHECKPOINTS.get() * 2) * 4)
+            .expect("non-zero times non-zero is non-zero; qed")
+    }
+
+    #[test]
+    fn verify_parallel_matches_sequential_verify() {
+        let seed = PotSeed::default();
+        let iterations = test_iterations();
+        let checkpoints =
+            prove(seed, iterations).expect("iterations is a multiple of checkpoints times two; qed");
+
+        assert_eq!(verify(seed, iterations, checkpoints.as_ref()), Ok(true));
+        assert_eq!(
+            verify_parallel(seed, iterations, checkpoints.as_ref()),
+            Ok(true)
+        );
+    }
+
+    #[test]
+    fn verify_parallel_rejects_the_same_tampering_as_sequential_verify() {
+        let seed = PotSeed::default();
+        let iterations = test_iterations();
+        let mut checkpoints: Vec<PotOutput> =
+            prove(seed, iterations)
+                .expect("iterations is a multiple of checkpoints times two; qed")
+                .as_ref()
+                .to_vec();
+        // Corrupt a single checkpoint so both verify functions must agree it is now invalid.
+        let last = checkpoints.len() - 1;
+        checkpoints[0] = checkpoints[last];
+
+        assert_eq!(verify(seed, iterations, &checkpoints), Ok(false));
+        assert_eq!(verify_parallel(seed, iterations, &checkpoints), Ok(false));
+    }
+}